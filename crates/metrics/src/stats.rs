@@ -4,12 +4,208 @@ use num_traits::float::Float;
 /// Extends the metric api with statistical aggregation functions
 use stats::{Commute, OnlineStats};
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
     fmt,
     fmt::{Display, Formatter},
     iter::FromIterator,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
+/// The physical unit a `Metric`'s value is reported in. Carried through to
+/// `DescriptiveStats`/`StatsRecord` so the CSV and any future exporter are
+/// self-describing rather than bare floats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum Unit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    Bytes,
+    Kibibytes,
+    Mebibytes,
+    #[default]
+    Count,
+    Percent,
+}
+
+impl Unit {
+    /// The canonical unit for this unit's family: all durations normalize to
+    /// `Nanoseconds`, all sizes to `Bytes`; `Count` and `Percent` are their
+    /// own canonical unit. Aggregation always happens in canonical units so
+    /// a source reporting some latencies in `ns` and some in `ms` still
+    /// combines correctly.
+    pub fn canonical(self) -> Unit {
+        match self {
+            Unit::Nanoseconds | Unit::Microseconds | Unit::Milliseconds | Unit::Seconds => {
+                Unit::Nanoseconds
+            }
+            Unit::Bytes | Unit::Kibibytes | Unit::Mebibytes => Unit::Bytes,
+            Unit::Count => Unit::Count,
+            Unit::Percent => Unit::Percent,
+        }
+    }
+
+    /// The multiplier that converts a value in this unit into its
+    /// `canonical` unit, e.g. `Unit::Milliseconds.canonical_scale() ==
+    /// 1_000_000.0` since a millisecond is a million nanoseconds.
+    pub fn canonical_scale(self) -> f64 {
+        match self {
+            Unit::Nanoseconds => 1.0,
+            Unit::Microseconds => 1_000.0,
+            Unit::Milliseconds => 1_000_000.0,
+            Unit::Seconds => 1_000_000_000.0,
+            Unit::Bytes => 1.0,
+            Unit::Kibibytes => 1024.0,
+            Unit::Mebibytes => 1024.0 * 1024.0,
+            Unit::Count => 1.0,
+            Unit::Percent => 1.0,
+        }
+    }
+
+    fn to_canonical(self, value: f64) -> f64 {
+        value * self.canonical_scale()
+    }
+}
+
+/// A single streaming quantile estimator using the P² (P-square) algorithm
+/// (Jain & Chlamtac, 1985): five markers track an approximate quantile in
+/// constant memory, without ever storing the samples that produced it.
+#[derive(Debug, Clone, Copy)]
+struct P2Quantile {
+    q: f64,
+    /// Marker positions.
+    n: [f64; 5],
+    /// Desired marker positions.
+    np: [f64; 5],
+    /// Desired position increments.
+    dn: [f64; 5],
+    /// Marker heights; `h[2]` (the middle marker) is the quantile estimate.
+    h: [f64; 5],
+    /// How many of the first five samples have been seen so far.
+    filled: usize,
+}
+
+impl P2Quantile {
+    fn new(q: f64) -> Self {
+        Self {
+            q,
+            n: [0.0; 5],
+            np: [0.0; 5],
+            dn: [0.0, q / 2.0, q, (1.0 + q) / 2.0, 1.0],
+            h: [0.0; 5],
+            filled: 0,
+        }
+    }
+
+    fn add(&mut self, x: f64) {
+        if x.is_nan() {
+            log::warn!("dropping NaN sample from P2 quantile estimator");
+            return;
+        }
+
+        if self.filled < 5 {
+            self.h[self.filled] = x;
+            self.filled += 1;
+            if self.filled == 5 {
+                self.h
+                    .sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+                for i in 0..5 {
+                    self.n[i] = (i + 1) as f64;
+                }
+                let q = self.q;
+                self.np = [1.0, 1.0 + 2.0 * q, 1.0 + 4.0 * q, 3.0 + 2.0 * q, 5.0];
+            }
+            return;
+        }
+
+        if x < self.h[0] {
+            self.h[0] = x;
+        } else if x > self.h[4] {
+            self.h[4] = x;
+        }
+
+        let mut k = 3;
+        for cell in 0..4 {
+            if x < self.h[cell + 1] {
+                k = cell;
+                break;
+            }
+        }
+        for i in (k + 1)..5 {
+            self.n[i] += 1.0;
+        }
+        for i in 0..5 {
+            self.np[i] += self.dn[i];
+        }
+
+        for i in 1..4 {
+            let d = self.np[i] - self.n[i];
+            let should_adjust = (d >= 1.0 && self.n[i + 1] - self.n[i] > 1.0)
+                || (d <= -1.0 && self.n[i - 1] - self.n[i] < -1.0);
+            if !should_adjust {
+                continue;
+            }
+            let d = d.signum();
+            let (n_prev, n_cur, n_next) = (self.n[i - 1], self.n[i], self.n[i + 1]);
+            let (h_prev, h_cur, h_next) = (self.h[i - 1], self.h[i], self.h[i + 1]);
+            let parabolic = h_cur
+                + d / (n_next - n_prev)
+                    * ((n_cur - n_prev + d) * (h_next - h_cur) / (n_next - n_cur)
+                        + (n_next - n_cur - d) * (h_cur - h_prev) / (n_cur - n_prev));
+            let new_h = if h_prev < parabolic && parabolic < h_next {
+                parabolic
+            } else {
+                let j = if d > 0.0 { i + 1 } else { i - 1 };
+                h_cur + d * (self.h[j] - h_cur) / (self.n[j] - n_cur)
+            };
+            self.h[i] = new_h;
+            self.n[i] += d;
+        }
+    }
+
+    fn value(&self) -> f64 {
+        if self.filled < 5 {
+            let mut sorted = self.h;
+            sorted[..self.filled]
+                .sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            if self.filled == 0 {
+                0.0
+            } else {
+                let idx = ((self.filled - 1) as f64 * self.q).round() as usize;
+                sorted[idx]
+            }
+        } else {
+            self.h[2]
+        }
+    }
+
+    fn count(&self) -> u64 {
+        if self.filled < 5 {
+            self.filled as u64
+        } else {
+            self.n[4] as u64
+        }
+    }
+
+    /// Cross-shard P² merges are approximate: there's no exact way to
+    /// combine two sets of five markers into one, so this averages marker
+    /// heights weighted by each side's observed count.
+    fn merge(&mut self, rhs: Self) {
+        let total = self.count() + rhs.count();
+        if total == 0 {
+            return;
+        }
+        let (w_self, w_rhs) = (self.count() as f64, rhs.count() as f64);
+        for i in 0..5 {
+            self.h[i] = (self.h[i] * w_self + rhs.h[i] * w_rhs) / total as f64;
+            self.n[i] += rhs.n[i];
+            self.np[i] += rhs.np[i];
+        }
+        self.filled = self.filled.max(rhs.filled);
+    }
+}
+
 /// An extension of `OnlineStats` that also incrementally tracks
 /// max and min values.
 #[derive(Debug, Clone)]
@@ -18,6 +214,10 @@ pub struct DescriptiveStats {
     max: f64,
     min: f64,
     cnt: u64,
+    p50: P2Quantile,
+    p95: P2Quantile,
+    p99: P2Quantile,
+    unit: Unit,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -29,6 +229,10 @@ pub struct StatsRecord {
     pub mean: f64,
     pub variance: f64,
     pub stddev: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub unit: Unit,
 }
 
 #[derive(Shrinkwrap, Clone)]
@@ -41,6 +245,80 @@ impl StatsRecord {
         record.name = Some(metric_name);
         record
     }
+
+    /// Reads out the value of a single named statistic, mirroring
+    /// `DescriptiveStats::stat` so a baseline `StatsRecord` and a live
+    /// `DescriptiveStats` can be compared on the same configured stat type.
+    pub fn stat(&self, stat_type: &DescriptiveStatType) -> f64 {
+        match stat_type {
+            DescriptiveStatType::Mean => self.mean,
+            DescriptiveStatType::Max => self.max,
+            DescriptiveStatType::Min => self.min,
+            DescriptiveStatType::StdDev => self.stddev,
+            DescriptiveStatType::Count => self.cnt as f64,
+        }
+    }
+
+    /// Writes `value` into whichever field `stat_type` names, the inverse of
+    /// `stat`.
+    fn set_stat(&mut self, stat_type: &DescriptiveStatType, value: f64) {
+        match stat_type {
+            DescriptiveStatType::Mean => self.mean = value,
+            DescriptiveStatType::Max => self.max = value,
+            DescriptiveStatType::Min => self.min = value,
+            DescriptiveStatType::StdDev => self.stddev = value,
+            DescriptiveStatType::Count => self.cnt = value as u64,
+        }
+    }
+
+    /// Formats `mean`/`min`/`max` with their unit suffix, auto-scaling the
+    /// magnitude for readability, e.g. `1500000 ns` -> `1.5 ms`,
+    /// `1048576 bytes` -> `1 MiB`. Values are assumed to already be in
+    /// `self.unit`'s canonical scale, which is what `StatsByMetric` stores.
+    pub fn human_display(&self) -> String {
+        format!(
+            "mean: {}, min: {}, max: {}",
+            Self::scaled(self.mean, self.unit),
+            Self::scaled(self.min, self.unit),
+            Self::scaled(self.max, self.unit),
+        )
+    }
+
+    fn scaled(value: f64, unit: Unit) -> String {
+        let (scaled_value, suffix) = match unit {
+            Unit::Nanoseconds => {
+                if value.abs() >= 1_000_000_000.0 {
+                    (value / 1_000_000_000.0, "s")
+                } else if value.abs() >= 1_000_000.0 {
+                    (value / 1_000_000.0, "ms")
+                } else if value.abs() >= 1_000.0 {
+                    (value / 1_000.0, "us")
+                } else {
+                    (value, "ns")
+                }
+            }
+            Unit::Bytes => {
+                if value.abs() >= 1024.0 * 1024.0 {
+                    (value / (1024.0 * 1024.0), "MiB")
+                } else if value.abs() >= 1024.0 {
+                    (value / 1024.0, "KiB")
+                } else {
+                    (value, "bytes")
+                }
+            }
+            Unit::Percent => (value, "%"),
+            _ => (value, "count"),
+        };
+        format!("{} {}", trim_trailing_zeros(scaled_value), suffix)
+    }
+}
+
+/// Formats `value` to two decimal places and trims trailing zeros (and a
+/// trailing `.`), so `1.0` displays as `1` and `1.5` as `1.5`.
+fn trim_trailing_zeros(value: f64) -> String {
+    let formatted = format!("{:.2}", value);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    trimmed.to_string()
 }
 
 impl From<DescriptiveStats> for StatsRecord {
@@ -53,13 +331,17 @@ impl From<DescriptiveStats> for StatsRecord {
             mean: desc_stats.mean(),
             variance: desc_stats.variance(),
             cnt: desc_stats.count(),
+            p50: desc_stats.quantile(0.5),
+            p95: desc_stats.quantile(0.95),
+            p99: desc_stats.quantile(0.99),
+            unit: desc_stats.unit,
         }
     }
 }
 
 impl Copy for DescriptiveStats {}
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum DescriptiveStatType {
     Mean,
     Max,
@@ -98,11 +380,28 @@ impl DescriptiveStats {
             max: f64::min_value(),
             min: f64::max_value(),
             cnt: 0,
+            p50: P2Quantile::new(0.5),
+            p95: P2Quantile::new(0.95),
+            p99: P2Quantile::new(0.99),
+            unit: Unit::default(),
         }
     }
 
-    /// Adds a value to the running statistic.
+    /// The unit that values added to this statistic are expressed in.
+    pub fn unit(&self) -> Unit {
+        self.unit
+    }
+
+    /// Adds a value to the running statistic. NaN is dropped with a warning
+    /// rather than recorded: `OnlineStats`' running mean/variance would
+    /// otherwise poison to NaN permanently from a single bad sample, and
+    /// `cnt` would diverge from the quantile estimators' sample count.
     pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            log::warn!("dropping NaN sample from descriptive stats");
+            return;
+        }
+
         self.online_stats.add(value);
         if value > self.max {
             self.max = value
@@ -111,6 +410,31 @@ impl DescriptiveStats {
             self.min = value
         }
         self.cnt += 1;
+        self.p50.add(value);
+        self.p95.add(value);
+        self.p99.add(value);
+    }
+
+    /// Streaming estimate of the `q`th quantile (e.g. `0.5` for the median),
+    /// computed via the P² algorithm without storing any samples. Only the
+    /// `p50`/`p95`/`p99` quantiles tracked by `add` are available; any other
+    /// `q` returns `f64::NAN`.
+    ///
+    /// Note that merging two `DescriptiveStats` (via `Commute::merge`, e.g.
+    /// to combine per-shard stats) only approximates the combined quantile:
+    /// there's no exact way to merge two sets of P² markers, so the merged
+    /// estimate is a count-weighted average of marker heights rather than a
+    /// recomputation from the underlying samples.
+    pub fn quantile(&self, q: f64) -> f64 {
+        if (q - 0.5).abs() < f64::EPSILON {
+            self.p50.value()
+        } else if (q - 0.95).abs() < f64::EPSILON {
+            self.p95.value()
+        } else if (q - 0.99).abs() < f64::EPSILON {
+            self.p99.value()
+        } else {
+            f64::nan()
+        }
     }
 
     /// The mean value of the running statistic.
@@ -142,6 +466,19 @@ impl DescriptiveStats {
     pub fn count(&self) -> u64 {
         self.cnt
     }
+
+    /// Reads out the value of a single named statistic, e.g. for comparing
+    /// just the `mean` of two `DescriptiveStats` without matching on every
+    /// field by hand.
+    pub fn stat(&self, stat_type: &DescriptiveStatType) -> f64 {
+        match stat_type {
+            DescriptiveStatType::Mean => self.mean(),
+            DescriptiveStatType::Max => self.max(),
+            DescriptiveStatType::Min => self.min(),
+            DescriptiveStatType::StdDev => self.stddev(),
+            DescriptiveStatType::Count => self.cnt as f64,
+        }
+    }
 }
 
 pub trait StatCheck {
@@ -227,6 +564,11 @@ impl dyn StatCheck {
     }
 }
 
+/// Merging `p50`/`p95`/`p99` is approximate: P²'s five markers can't be
+/// combined exactly, so the merged quantile estimate is a count-weighted
+/// average of marker heights rather than what recomputing from the
+/// underlying samples would give. `mean`/`stddev`/`max`/`min`/`cnt` merge
+/// exactly.
 impl Commute for DescriptiveStats {
     fn merge(&mut self, rhs: Self) {
         self.online_stats.merge(rhs.online_stats);
@@ -237,6 +579,13 @@ impl Commute for DescriptiveStats {
             self.min = rhs.min
         }
         self.cnt += rhs.cnt;
+        self.p50.merge(rhs.p50);
+        self.p95.merge(rhs.p95);
+        self.p99.merge(rhs.p99);
+        if self.cnt == rhs.cnt {
+            // `self` held no samples before this merge, so it takes `rhs`'s unit.
+            self.unit = rhs.unit;
+        }
     }
 }
 
@@ -262,20 +611,272 @@ impl StatsByMetric {
         writer.flush()?;
         Ok(())
     }
+
+    /// Renders these stats as Prometheus text-exposition format, so a
+    /// running node can expose them on a `/metrics` endpoint for scraping
+    /// instead of only dumping CSV at exit. Metric names are sanitized to
+    /// `[a-zA-Z_:][a-zA-Z0-9_:]*` as required by the exposition format.
+    ///
+    /// `_count` is exposed as a `gauge` rather than a `counter`: a
+    /// `StatsByMetric` snapshotted off an `AtomicBucket` window has its
+    /// count reset on every `flush`, which would violate the monotonicity
+    /// Prometheus counters (and `rate()`/`increase()` queries over them)
+    /// require.
+    pub fn to_prometheus(&self) -> String {
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+
+        let mut out = String::new();
+        for name in names {
+            let stat = &self.0[name];
+            let metric_name = sanitize_prometheus_name(name);
+
+            out.push_str(&format!("# TYPE {}_mean gauge\n", metric_name));
+            out.push_str(&format!("{}_mean {}\n", metric_name, stat.mean()));
+            out.push_str(&format!("# TYPE {}_stddev gauge\n", metric_name));
+            out.push_str(&format!("{}_stddev {}\n", metric_name, stat.stddev()));
+            out.push_str(&format!("# TYPE {}_min gauge\n", metric_name));
+            out.push_str(&format!("{}_min {}\n", metric_name, stat.min()));
+            out.push_str(&format!("# TYPE {}_max gauge\n", metric_name));
+            out.push_str(&format!("{}_max {}\n", metric_name, stat.max()));
+            out.push_str(&format!("# TYPE {}_count gauge\n", metric_name));
+            out.push_str(&format!("{}_count {}\n", metric_name, stat.count()));
+        }
+        out
+    }
+}
+
+/// Sanitizes a metric name to the Prometheus exposition format's allowed
+/// identifier charset, `[a-zA-Z_:][a-zA-Z0-9_:]*`, replacing disallowed
+/// characters with `_` and prefixing a leading digit.
+fn sanitize_prometheus_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized
+        .chars()
+        .next()
+        .map(|c| c.is_ascii_digit())
+        .unwrap_or(true)
+    {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Serves a single Prometheus scrape over a bare `TcpListener`, for ad-hoc
+/// exposition of node metrics without pulling in a full HTTP server. Enabled
+/// via the `metrics_server` feature; a long-running deployment should front
+/// this with a real HTTP server instead.
+#[cfg(feature = "metrics_server")]
+pub fn serve_metrics_once<A: std::net::ToSocketAddrs>(
+    stats: &StatsByMetric,
+    addr: A,
+) -> std::io::Result<()> {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind(addr)?;
+    let (mut stream, _) = listener.accept()?;
+    let mut buf = [0u8; 1024];
+    let _ = stream.read(&mut buf)?;
+
+    let body = stats.to_prometheus();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+/// Derives named published statistics ("scores") from a `DescriptiveStats`,
+/// in the spirit of dipstick's `ScoreType` -> publishable-stat transform.
+/// Boxing as a trait object lets a `ScoreSet` hold a heterogeneous,
+/// per-metric-configurable list of them.
+pub type ScoreFn = dyn Fn(&DescriptiveStats, &str) -> Vec<(String, f64)> + Send + Sync;
+
+/// The default score: the same mean/min/max/stddev/count/p50/p95/p99 that
+/// `StatsRecord` reports, expressed as a `ScoreFn`.
+pub fn default_summary(stats: &DescriptiveStats, _metric_name: &str) -> Vec<(String, f64)> {
+    vec![
+        ("mean".to_string(), stats.mean()),
+        ("min".to_string(), stats.min()),
+        ("max".to_string(), stats.max()),
+        ("stddev".to_string(), stats.stddev()),
+        ("count".to_string(), stats.count() as f64),
+        ("p50".to_string(), stats.quantile(0.5)),
+        ("p95".to_string(), stats.quantile(0.95)),
+        ("p99".to_string(), stats.quantile(0.99)),
+    ]
+}
+
+/// The running total of all added values (`mean * count`).
+pub fn sum_score(stats: &DescriptiveStats, _metric_name: &str) -> Vec<(String, f64)> {
+    vec![("sum".to_string(), stats.mean() * stats.count() as f64)]
+}
+
+/// Just the `p50`/`p95`/`p99` quantiles tracked by `DescriptiveStats::add`.
+pub fn quantile_scores(stats: &DescriptiveStats, _metric_name: &str) -> Vec<(String, f64)> {
+    vec![
+        ("p50".to_string(), stats.quantile(0.5)),
+        ("p95".to_string(), stats.quantile(0.95)),
+        ("p99".to_string(), stats.quantile(0.99)),
+    ]
+}
+
+/// Builds a `ScoreFn` that reports `count` divided by a fixed elapsed-time
+/// handle, turning a raw sample count into a throughput figure. The elapsed
+/// time is captured when the `ScoreFn` is built, since `DescriptiveStats`
+/// itself has no notion of wall-clock time.
+pub fn rate_score(elapsed: Duration) -> Box<ScoreFn> {
+    let elapsed_secs = elapsed.as_secs_f64();
+    Box::new(move |stats: &DescriptiveStats, _metric_name: &str| {
+        let rate = if elapsed_secs > 0.0 {
+            stats.count() as f64 / elapsed_secs
+        } else {
+            0.0
+        };
+        vec![("rate".to_string(), rate)]
+    })
+}
+
+/// A named, heterogeneous record produced by running a metric's active
+/// `ScoreFn`s against its `DescriptiveStats`, e.g.
+/// `{ name: "latency", values: [("p99", 12.0), ...] }`.
+#[derive(Debug, Clone)]
+pub struct ScoredRecord {
+    pub name: String,
+    pub values: Vec<(String, f64)>,
+}
+
+/// A configurable, per-metric list of `ScoreFn`s to materialize, alongside
+/// (not replacing) the fixed mean/min/max/stddev/count fields `StatsRecord`
+/// always reports - e.g. emitting only `count`/`rate` for throughput metrics
+/// but full quantiles for latency metrics - while leaving the underlying
+/// `Commute`/`DescriptiveStats` aggregation untouched. Use `to_records`/
+/// `print_csv` when the fixed `StatsRecord` shape is what's wanted, and
+/// `to_scored_records`/`print_scored_csv` when per-metric control over what
+/// gets reported matters more than a uniform schema.
+#[derive(Default)]
+pub struct ScoreSet {
+    default: Vec<Box<ScoreFn>>,
+    overrides: HashMap<String, Vec<Box<ScoreFn>>>,
+}
+
+impl ScoreSet {
+    /// A `ScoreSet` that reports `default_summary` for every metric, i.e.
+    /// the statistics `StatsByMetric::to_records` already produces, minus
+    /// `variance`.
+    pub fn summary() -> Self {
+        let mut set = Self::default();
+        set.default.push(Box::new(default_summary));
+        set
+    }
+
+    /// Overrides the scores materialized for `metric_name`, replacing
+    /// whatever the default (or a previous override) would have produced.
+    pub fn set_scores_for<S: Into<String>>(&mut self, metric_name: S, scores: Vec<Box<ScoreFn>>) {
+        self.overrides.insert(metric_name.into(), scores);
+    }
+
+    fn scores_for(&self, metric_name: &str) -> &[Box<ScoreFn>] {
+        self.overrides.get(metric_name).unwrap_or(&self.default)
+    }
+}
+
+impl StatsByMetric {
+    /// Writes each metric's active `ScoreFn` output (per `scores`) as CSV in
+    /// tidy/long form, one `metric,score,value` row per score. Unlike
+    /// `print_csv`'s fixed `StatsRecord` columns, a `ScoreSet` can configure
+    /// a different set of scores per metric, so there's no single wide-format
+    /// header to write them under.
+    pub fn print_scored_csv(&self, scores: &ScoreSet) -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = csv::Writer::from_writer(std::io::stdout());
+        writer.write_record(["metric", "score", "value"])?;
+        for record in self.to_scored_records(scores) {
+            for (score_name, value) in &record.values {
+                writer.write_record([record.name.as_str(), score_name.as_str(), &value.to_string()])?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Materializes each metric's active `ScoreFn`s (per `scores`) into a
+    /// `ScoredRecord`, so callers can choose per-metric which derived
+    /// statistics get reported, as an alternative to the fixed `StatsRecord`
+    /// shape that `to_records` always produces.
+    pub fn to_scored_records(&self, scores: &ScoreSet) -> Vec<ScoredRecord> {
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let stats = &self.0[name];
+                let values = scores
+                    .scores_for(name)
+                    .iter()
+                    .flat_map(|score_fn| score_fn(stats, name))
+                    .collect();
+                ScoredRecord {
+                    name: name.clone(),
+                    values,
+                }
+            })
+            .collect()
+    }
+}
+
+impl StatsByMetric {
+    /// Folds a single `Metric` into this map, creating a fresh
+    /// `DescriptiveStats` for a metric name seen for the first time, and
+    /// otherwise either `add`ing the (unit-normalized) value or dropping it
+    /// with a warning if its unit is incompatible with what's already
+    /// recorded. Shared by `FromIterator<Metric>` and `AtomicBucket::ingest`.
+    fn accumulate(&mut self, metric: Metric) {
+        let canonical_value = metric.unit.to_canonical(metric.value);
+        match self.0.entry(metric.name.clone()) {
+            Entry::Occupied(mut occupied) => {
+                let existing = occupied.get_mut();
+                if existing.unit().canonical() == metric.unit.canonical() {
+                    existing.add(canonical_value);
+                } else {
+                    log::warn!(
+                        "dropping sample for metric `{}`: unit {:?} is incompatible with \
+                         the {:?} already recorded for it",
+                        metric.name,
+                        metric.unit,
+                        existing.unit(),
+                    );
+                }
+            }
+            Entry::Vacant(vacant) => {
+                let mut stats = DescriptiveStats::empty();
+                stats.unit = metric.unit.canonical();
+                stats.add(canonical_value);
+                vacant.insert(stats);
+            }
+        }
+    }
 }
 
 impl FromIterator<Metric> for StatsByMetric {
     fn from_iter<I: IntoIterator<Item = Metric>>(source: I) -> StatsByMetric {
-        StatsByMetric(source.into_iter().fold(
-            HashMap::new(),
-            |mut stats_by_metric_name, metric| {
-                let entry = stats_by_metric_name.entry(metric.name);
-
-                let online_stats = entry.or_insert_with(DescriptiveStats::empty);
-                online_stats.add(metric.value);
-                stats_by_metric_name
-            },
-        ))
+        let mut stats_by_metric_name = StatsByMetric(HashMap::new());
+        for metric in source {
+            stats_by_metric_name.accumulate(metric);
+        }
+        stats_by_metric_name
     }
 }
 
@@ -289,6 +890,244 @@ impl Commute for StatsByMetric {
     }
 }
 
+/// A rolling aggregation window for a live stream of `Metric` values.
+/// `ingest` takes `&self` (behind interior mutability) so many concurrent
+/// producers can feed it, and `flush` atomically swaps the in-progress
+/// `StatsByMetric` out for a fresh, empty one, returning an immutable
+/// snapshot of the window that just closed. Snapshots still implement
+/// `Commute`, so a coordinator can merge windows across nodes into a global
+/// view, turning the accumulate-everything-then-`from_iter` model into a
+/// rolling windowed reporter suitable for a long-running node.
+pub struct AtomicBucket {
+    current: Mutex<StatsByMetric>,
+    interval: Option<Duration>,
+    last_flush: Mutex<Instant>,
+}
+
+impl AtomicBucket {
+    /// A bucket with no configured flush interval; only `flush()` ever
+    /// closes a window.
+    pub fn new() -> Self {
+        Self {
+            current: Mutex::new(StatsByMetric(HashMap::new())),
+            interval: None,
+            last_flush: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// A bucket that `flush_if_due` will close every `interval`.
+    pub fn with_interval(interval: Duration) -> Self {
+        Self {
+            interval: Some(interval),
+            ..Self::new()
+        }
+    }
+
+    /// Adds a metric to the window currently being accumulated.
+    pub fn ingest(&self, metric: Metric) {
+        let mut current = self.current.lock().expect("AtomicBucket mutex poisoned");
+        current.accumulate(metric);
+    }
+
+    /// Atomically swaps the in-progress map out for a fresh, empty one and
+    /// returns an immutable snapshot of the window that just closed.
+    pub fn flush(&self) -> StatsByMetric {
+        let mut current = self.current.lock().expect("AtomicBucket mutex poisoned");
+        *self
+            .last_flush
+            .lock()
+            .expect("AtomicBucket mutex poisoned") = Instant::now();
+        std::mem::replace(&mut *current, StatsByMetric(HashMap::new()))
+    }
+
+    /// Flushes the window if at least `interval` has elapsed since the last
+    /// flush, returning `None` otherwise or if no interval was configured.
+    /// Callers on a long-running node poll this (e.g. once per event-loop
+    /// tick) rather than driving flushes off a dedicated timer thread.
+    pub fn flush_if_due(&self) -> Option<StatsByMetric> {
+        let interval = self.interval?;
+        let due = self
+            .last_flush
+            .lock()
+            .expect("AtomicBucket mutex poisoned")
+            .elapsed()
+            >= interval;
+        if due {
+            Some(self.flush())
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for AtomicBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which direction of change counts as an improvement for a given metric.
+/// Most metrics this module aggregates are costs (latency, memory, ...) where
+/// a smaller number is better, hence the default.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Default)]
+pub enum RatchetDirection {
+    #[default]
+    LowerIsBetter,
+    HigherIsBetter,
+}
+
+/// The outcome of comparing one metric's current value against its ratchet
+/// baseline.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum MetricChange {
+    Improvement { old: f64, new: f64 },
+    Regression { old: f64, new: f64 },
+    NoChange,
+}
+
+/// A persistent regression gate, modeled on the classic test-driver "metrics
+/// ratchet": a JSON baseline of previously observed `DescriptiveStats`
+/// records that a later run is compared against. Runs within `noise` of the
+/// baseline are `NoChange`; runs that move the wrong way for a metric's
+/// `RatchetDirection` are `Regression`s that should fail CI; runs that move
+/// the right way are `Improvement`s, and `save`ing after a ratchet tightens
+/// the baseline to the new, better value so it can never regress back.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MetricRatchet {
+    baseline: HashMap<String, StatsRecord>,
+    #[serde(default)]
+    noise: HashMap<String, f64>,
+    #[serde(default)]
+    stat_type: HashMap<String, DescriptiveStatType>,
+    #[serde(default)]
+    direction: HashMap<String, RatchetDirection>,
+}
+
+impl MetricRatchet {
+    /// Loads a baseline previously written by `to_json`.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    /// Serializes the current baseline so it can be committed as the next
+    /// run's comparison point.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Tolerance at or below which a delta for `metric_name` is treated as noise
+    /// rather than a genuine improvement or regression. Defaults to `0.0`.
+    pub fn noise_for(&self, metric_name: &str) -> f64 {
+        self.noise.get(metric_name).copied().unwrap_or(0.0)
+    }
+
+    /// Sets the noise tolerance used when comparing `metric_name` against
+    /// its baseline.
+    pub fn set_noise<S: Into<String>>(&mut self, metric_name: S, noise: f64) {
+        self.noise.insert(metric_name.into(), noise);
+    }
+
+    /// Sets which `DescriptiveStatType` is compared against the baseline for
+    /// `metric_name`. Defaults to `Mean`.
+    pub fn set_stat_type<S: Into<String>>(&mut self, metric_name: S, stat_type: DescriptiveStatType) {
+        self.stat_type.insert(metric_name.into(), stat_type);
+    }
+
+    /// Sets which direction of change counts as an improvement for
+    /// `metric_name`. Defaults to `RatchetDirection::LowerIsBetter`.
+    pub fn set_direction<S: Into<String>>(&mut self, metric_name: S, direction: RatchetDirection) {
+        self.direction.insert(metric_name.into(), direction);
+    }
+
+    fn stat_type_for(&self, metric_name: &str) -> DescriptiveStatType {
+        self.stat_type
+            .get(metric_name)
+            .cloned()
+            .unwrap_or(DescriptiveStatType::Mean)
+    }
+
+    fn direction_for(&self, metric_name: &str) -> RatchetDirection {
+        self.direction.get(metric_name).copied().unwrap_or_default()
+    }
+
+    /// Compares `actual` against the baseline, classifying every metric
+    /// present in `actual` as an `Improvement`, `Regression` or `NoChange`.
+    /// Metrics missing from the baseline are reported as an `Improvement`
+    /// over an implicit "no data" baseline; use `ratchet_strict` in CI if
+    /// that should instead be a hard failure.
+    pub fn ratchet(&mut self, actual: &StatsByMetric) -> HashMap<String, MetricChange> {
+        self.ratchet_strict(actual)
+            .unwrap_or_else(|(changes, _missing)| changes)
+    }
+
+    /// As `ratchet`, but returns `Err` carrying the changes computed so far
+    /// plus the names of any metric present in `actual` with no baseline
+    /// entry, instead of silently treating it as an improvement.
+    #[allow(clippy::type_complexity)]
+    pub fn ratchet_strict(
+        &self,
+        actual: &StatsByMetric,
+    ) -> Result<HashMap<String, MetricChange>, (HashMap<String, MetricChange>, Vec<String>)> {
+        let mut changes = HashMap::new();
+        let mut missing = Vec::new();
+
+        for (metric_name, actual_stat) in actual.iter() {
+            let stat_type = self.stat_type_for(metric_name);
+            let new = actual_stat.stat(&stat_type);
+
+            let old = match self.baseline.get(metric_name) {
+                Some(record) => record.stat(&stat_type),
+                None => {
+                    missing.push(metric_name.clone());
+                    changes.insert(metric_name.clone(), MetricChange::Improvement { old: new, new });
+                    continue;
+                }
+            };
+
+            let noise = self.noise_for(metric_name);
+            let delta = new - old;
+
+            let change = if delta.abs() <= noise {
+                MetricChange::NoChange
+            } else {
+                let improved = match self.direction_for(metric_name) {
+                    RatchetDirection::LowerIsBetter => delta < 0.0,
+                    RatchetDirection::HigherIsBetter => delta > 0.0,
+                };
+                if improved {
+                    MetricChange::Improvement { old, new }
+                } else {
+                    MetricChange::Regression { old, new }
+                }
+            };
+            changes.insert(metric_name.clone(), change);
+        }
+
+        if missing.is_empty() {
+            Ok(changes)
+        } else {
+            Err((changes, missing))
+        }
+    }
+
+    /// Tightens the baseline in place: every metric whose `changes` entry is
+    /// an `Improvement` has its baseline replaced with the new value. Calling
+    /// `to_json` afterwards persists the tightened ratchet, so the accepted
+    /// bound only ever gets stricter.
+    pub fn save(&mut self, actual: &StatsByMetric, changes: &HashMap<String, MetricChange>) {
+        for (metric_name, change) in changes {
+            if let MetricChange::Improvement { new, .. } = change {
+                if let Some(actual_stat) = actual.get(metric_name) {
+                    let stat_type = self.stat_type_for(metric_name);
+                    let mut record = StatsRecord::new(metric_name.clone(), *actual_stat);
+                    record.set_stat(&stat_type, *new);
+                    self.baseline.insert(metric_name.clone(), record);
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -317,4 +1156,202 @@ mod tests {
         assert_eq!(size_stats.min(), 1.0);
         assert_eq!(size_stats.max(), 100.0);
     }
+
+    #[test]
+    fn ratchet_classifies_improvement_regression_and_no_change() {
+        let mut baseline = HashMap::new();
+        baseline.insert(
+            "latency".to_string(),
+            StatsRecord::new("latency", {
+                let mut d = DescriptiveStats::empty();
+                d.add(100.0);
+                d
+            }),
+        );
+        baseline.insert(
+            "size".to_string(),
+            StatsRecord::new("size", {
+                let mut d = DescriptiveStats::empty();
+                d.add(10.0);
+                d
+            }),
+        );
+        let mut ratchet = MetricRatchet {
+            baseline,
+            ..Default::default()
+        };
+
+        let actual = StatsByMetric::from_iter(vec![
+            Metric::new("latency", 80.0),
+            Metric::new("size", 10.0),
+        ]);
+
+        let changes = ratchet.ratchet(&actual);
+        assert_eq!(
+            changes.get("latency"),
+            Some(&MetricChange::Improvement {
+                old: 100.0,
+                new: 80.0
+            })
+        );
+        assert_eq!(changes.get("size"), Some(&MetricChange::NoChange));
+
+        ratchet.save(&actual, &changes);
+        assert_eq!(ratchet.baseline.get("latency").unwrap().mean, 80.0);
+    }
+
+    #[test]
+    fn ratchet_compares_configured_stat_type_on_both_sides() {
+        let mut baseline = HashMap::new();
+        baseline.insert(
+            "latency".to_string(),
+            StatsRecord::new("latency", {
+                let mut d = DescriptiveStats::empty();
+                d.add(10.0);
+                d.add(100.0);
+                d
+            }),
+        );
+        let mut ratchet = MetricRatchet {
+            baseline,
+            ..Default::default()
+        };
+        ratchet.set_stat_type("latency", DescriptiveStatType::Max);
+
+        // The configured stat is `Max`, which regressed from 100 to 150;
+        // the mean (55 before, about 86.7 now) is not what gets compared.
+        let actual = StatsByMetric::from_iter(vec![
+            Metric::new("latency", 10.0),
+            Metric::new("latency", 100.0),
+            Metric::new("latency", 150.0),
+        ]);
+
+        let changes = ratchet.ratchet(&actual);
+        assert_eq!(
+            changes.get("latency"),
+            Some(&MetricChange::Regression {
+                old: 100.0,
+                new: 150.0
+            })
+        );
+
+        ratchet.save(&actual, &changes);
+        // A regression must not be saved back into the baseline.
+        assert_eq!(ratchet.baseline.get("latency").unwrap().max, 100.0);
+    }
+
+    #[test]
+    fn quantile_estimates_median_of_uniform_samples() {
+        let mut stats = DescriptiveStats::empty();
+        for i in 1..=1000 {
+            stats.add(i as f64);
+        }
+
+        let median = stats.quantile(0.5);
+        assert!(
+            (median - 500.0).abs() < 25.0,
+            "expected median near 500.0, got {}",
+            median
+        );
+        assert!(stats.quantile(0.99) > stats.quantile(0.95));
+        assert!(stats.quantile(0.3).is_nan());
+    }
+
+    #[test]
+    fn quantile_drops_nan_samples_instead_of_panicking() {
+        let mut stats = DescriptiveStats::empty();
+        stats.add(f64::nan());
+        stats.add(10.0);
+        stats.add(20.0);
+
+        assert_eq!(stats.quantile(0.5), 20.0);
+        assert_eq!(stats.count(), 2);
+        assert_eq!(stats.mean(), 15.0);
+    }
+
+    #[test]
+    fn to_prometheus_sanitizes_names_and_emits_gauges() {
+        let stats = StatsByMetric::from_iter(vec![
+            Metric::new("net.latency-ms", 10.0),
+            Metric::new("net.latency-ms", 20.0),
+        ]);
+
+        let rendered = stats.to_prometheus();
+        assert!(rendered.contains("# TYPE net_latency_ms_mean gauge"));
+        assert!(rendered.contains("net_latency_ms_mean 15"));
+        assert!(rendered.contains("# TYPE net_latency_ms_count gauge"));
+        assert!(rendered.contains("net_latency_ms_count 2"));
+    }
+
+    #[test]
+    fn human_display_auto_scales_units() {
+        let mut stats = DescriptiveStats::empty();
+        stats.unit = Unit::Nanoseconds;
+        stats.add(1_500_000.0);
+        let record = StatsRecord::new("latency", stats);
+
+        assert_eq!(
+            record.human_display(),
+            "mean: 1.5 ms, min: 1.5 ms, max: 1.5 ms"
+        );
+    }
+
+    #[test]
+    fn canonical_scale_normalizes_mixed_units() {
+        assert_eq!(Unit::Milliseconds.canonical_scale(), 1_000_000.0);
+        assert_eq!(Unit::Milliseconds.canonical(), Unit::Nanoseconds);
+        assert_eq!(Unit::Kibibytes.canonical(), Unit::Bytes);
+    }
+
+    #[test]
+    fn score_set_applies_per_metric_overrides() {
+        let stats = StatsByMetric::from_iter(vec![
+            Metric::new("latency", 10.0),
+            Metric::new("latency", 20.0),
+            Metric::new("requests", 1.0),
+            Metric::new("requests", 1.0),
+        ]);
+
+        let mut scores = ScoreSet::summary();
+        scores.set_scores_for("requests", vec![Box::new(sum_score), rate_score(Duration::from_secs(2))]);
+
+        let records = stats.to_scored_records(&scores);
+        let latency = records.iter().find(|r| r.name == "latency").unwrap();
+        assert!(latency.values.iter().any(|(k, _)| k == "p99"));
+
+        let requests = records.iter().find(|r| r.name == "requests").unwrap();
+        let names: Vec<&str> = requests.values.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(names, vec!["sum", "rate"]);
+        assert_eq!(
+            requests.values.iter().find(|(k, _)| k == "rate").unwrap().1,
+            1.0
+        );
+    }
+
+    #[test]
+    fn atomic_bucket_flush_snapshots_and_resets_the_window() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let bucket = Arc::new(AtomicBucket::new());
+        let mut handles = Vec::new();
+        for _ in 0..4 {
+            let bucket = Arc::clone(&bucket);
+            handles.push(thread::spawn(move || {
+                for _ in 0..25 {
+                    bucket.ingest(Metric::new("latency", 10.0));
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let snapshot = bucket.flush();
+        let latency = snapshot.get("latency").expect("latency stats to be present");
+        assert_eq!(latency.count(), 100);
+
+        let empty_snapshot = bucket.flush();
+        assert!(empty_snapshot.get("latency").is_none());
+    }
 }